@@ -0,0 +1,272 @@
+use crate::BlocklistConfig;
+use futures_util::StreamExt;
+use serde_derive::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub type SharedBlocklist = Arc<RwLock<Blocklist>>;
+
+/// A set of blocked address ranges. Single addresses are stored as one-element
+/// ranges so CIDR entries and plain IPs share the same containment test.
+/// Addresses are widened to `u128` so both families share one code path, and
+/// ranges are kept sorted and coalesced into maximal non-overlapping intervals
+/// so membership is a sound binary search even when entries overlap.
+#[derive(Default)]
+pub struct Blocklist {
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedMessage {
+    // "add" or "remove"
+    action: String,
+    cidr: String,
+}
+
+impl Blocklist {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(v4) => range_contains(&self.v4, u128::from(u32::from(v4))),
+            IpAddr::V6(v6) => range_contains(&self.v6, u128::from(v6)),
+        }
+    }
+
+    /// Insert a plain IP or `addr/prefix` CIDR entry, coalescing it with any
+    /// overlapping ranges so the stored intervals stay non-overlapping.
+    pub fn insert(&mut self, entry: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (start, end, is_v6) = parse_range(entry)?;
+        let ranges = if is_v6 { &mut self.v6 } else { &mut self.v4 };
+        insert_range(ranges, (start, end));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, entry: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (start, end, is_v6) = parse_range(entry)?;
+        let ranges = if is_v6 { &mut self.v6 } else { &mut self.v4 };
+        remove_range(ranges, (start, end));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+/// Build the shared blocklist, seeding it from the configured file if present.
+pub async fn load(config: &BlocklistConfig) -> SharedBlocklist {
+    let mut blocklist = Blocklist::default();
+    if let Some(path) = &config.file {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let entry = line.trim();
+                    if entry.is_empty() || entry.starts_with('#') {
+                        continue;
+                    }
+                    if let Err(e) = blocklist.insert(entry) {
+                        log::error!("bad blocklist entry {:?}: {}", entry, e);
+                    }
+                }
+            }
+            Err(e) => log::error!("reading blocklist {}: {}", path, e),
+        }
+    }
+    log::info!("blocklist seeded with {} ranges", blocklist.len());
+    Arc::new(RwLock::new(blocklist))
+}
+
+/// Subscribe to the remote feed and apply streamed add/remove messages so the
+/// blocklist can be updated without a restart.
+pub async fn subscribe(feed: String, blocklist: SharedBlocklist) {
+    loop {
+        match async_tungstenite::tokio::connect_async(&feed).await {
+            Ok((mut ws, _resp)) => {
+                log::info!("blocklist feed connected: {}", feed);
+                while let Some(msg) = ws.next().await {
+                    let text = match msg {
+                        Ok(m) => m.into_text().unwrap_or_default(),
+                        Err(e) => {
+                            log::error!("blocklist feed error: {}", e);
+                            break;
+                        }
+                    };
+                    let update: FeedMessage = match serde_json::from_str(&text) {
+                        Ok(u) => u,
+                        Err(e) => {
+                            log::error!("bad feed message {:?}: {}", text, e);
+                            continue;
+                        }
+                    };
+                    let mut set = blocklist.write().await;
+                    match update.action.as_str() {
+                        "add" => {
+                            if let Err(e) = set.insert(&update.cidr) {
+                                log::error!("feed add {:?}: {}", update.cidr, e);
+                            }
+                        }
+                        "remove" => {
+                            if let Err(e) = set.remove(&update.cidr) {
+                                log::error!("feed remove {:?}: {}", update.cidr, e);
+                            }
+                        }
+                        other => log::error!("unknown feed action: {}", other),
+                    }
+                }
+            }
+            Err(e) => log::error!("blocklist feed connect {}: {}", feed, e),
+        }
+        // Reconnect after a short back-off if the feed drops.
+        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    }
+}
+
+fn range_contains(ranges: &[(u128, u128)], needle: u128) -> bool {
+    // Ranges are sorted by start and non-overlapping, so the only range that can
+    // contain the needle is the last one starting at or before it.
+    match ranges.binary_search_by(|&(start, _)| start.cmp(&needle)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(idx) => {
+            let (_, end) = ranges[idx - 1];
+            needle <= end
+        }
+    }
+}
+
+// Merge `new` with every range it overlaps or abuts, producing a single
+// maximal interval, then re-sort. Keeps the set non-overlapping.
+fn insert_range(ranges: &mut Vec<(u128, u128)>, mut new: (u128, u128)) {
+    let mut merged = Vec::with_capacity(ranges.len() + 1);
+    for &(start, end) in ranges.iter() {
+        // Overlapping or directly adjacent ranges fold into `new`.
+        if start <= new.1.saturating_add(1) && new.0.saturating_sub(1) <= end {
+            new.0 = new.0.min(start);
+            new.1 = new.1.max(end);
+        } else {
+            merged.push((start, end));
+        }
+    }
+    merged.push(new);
+    merged.sort();
+    *ranges = merged;
+}
+
+// Subtract `rem` from the set, splitting any range it cuts through so that a
+// specific entry can be lifted out of a broader one.
+fn remove_range(ranges: &mut Vec<(u128, u128)>, rem: (u128, u128)) {
+    let mut kept = Vec::with_capacity(ranges.len() + 1);
+    for &(start, end) in ranges.iter() {
+        if end < rem.0 || rem.1 < start {
+            kept.push((start, end)); // disjoint
+            continue;
+        }
+        if start < rem.0 {
+            kept.push((start, rem.0 - 1)); // left remainder
+        }
+        if rem.1 < end {
+            kept.push((rem.1 + 1, end)); // right remainder
+        }
+    }
+    kept.sort();
+    *ranges = kept;
+}
+
+// Parse a plain IP or `addr/prefix` CIDR into an inclusive numeric range,
+// widened to u128 so both families share one code path.
+fn parse_range(
+    entry: &str,
+) -> Result<(u128, u128, bool), Box<dyn std::error::Error + Send + Sync>> {
+    let (addr_str, prefix) = match entry.split_once('/') {
+        Some((a, p)) => (a, Some(p.parse::<u32>()?)),
+        None => (entry, None),
+    };
+
+    match addr_str.parse::<IpAddr>()? {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let prefix = prefix.unwrap_or(32);
+            if prefix > 32 {
+                return Err("ipv4 prefix out of range".into());
+            }
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            let start = bits & mask;
+            let end = start | !mask;
+            Ok((start as u128, end as u128, false))
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let prefix = prefix.unwrap_or(128);
+            if prefix > 128 {
+                return Err("ipv6 prefix out of range".into());
+            }
+            let mask = if prefix == 0 { 0 } else { u128::MAX << (128 - prefix) };
+            let start = bits & mask;
+            let end = start | !mask;
+            Ok((start, end, true))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn contains_plain_ip() {
+        let mut set = Blocklist::default();
+        set.insert("10.0.0.5").unwrap();
+        assert!(set.contains(Ipv4Addr::new(10, 0, 0, 5).into()));
+        assert!(!set.contains(Ipv4Addr::new(10, 0, 0, 6).into()));
+    }
+
+    #[test]
+    fn contains_cidr_range() {
+        let mut set = Blocklist::default();
+        set.insert("192.168.1.0/24").unwrap();
+        assert!(set.contains(Ipv4Addr::new(192, 168, 1, 0).into()));
+        assert!(set.contains(Ipv4Addr::new(192, 168, 1, 255).into()));
+        assert!(!set.contains(Ipv4Addr::new(192, 168, 2, 0).into()));
+    }
+
+    #[test]
+    fn insert_coalesces_overlapping_ranges() {
+        let mut set = Blocklist::default();
+        set.insert("10.0.0.0/24").unwrap();
+        set.insert("10.0.0.128/25").unwrap(); // fully contained by the /24 above
+        assert_eq!(set.v4, vec![(u32::from(Ipv4Addr::new(10, 0, 0, 0)) as u128,
+            u32::from(Ipv4Addr::new(10, 0, 0, 255)) as u128)]);
+    }
+
+    #[test]
+    fn insert_coalesces_adjacent_ranges() {
+        let mut set = Blocklist::default();
+        set.insert("10.0.0.0/25").unwrap(); // 10.0.0.0 - 10.0.0.127
+        set.insert("10.0.0.128/25").unwrap(); // 10.0.0.128 - 10.0.0.255, directly adjacent
+        assert_eq!(set.v4.len(), 1);
+        assert!(set.contains(Ipv4Addr::new(10, 0, 0, 127).into()));
+        assert!(set.contains(Ipv4Addr::new(10, 0, 0, 128).into()));
+    }
+
+    #[test]
+    fn remove_splits_a_broader_range() {
+        let mut set = Blocklist::default();
+        set.insert("10.0.0.0/24").unwrap();
+        set.remove("10.0.0.5").unwrap();
+        assert!(set.contains(Ipv4Addr::new(10, 0, 0, 4).into()));
+        assert!(!set.contains(Ipv4Addr::new(10, 0, 0, 5).into()));
+        assert!(set.contains(Ipv4Addr::new(10, 0, 0, 6).into()));
+    }
+
+    #[test]
+    fn remove_reports_malformed_entry() {
+        let mut set = Blocklist::default();
+        assert!(set.remove("not-an-ip").is_err());
+    }
+}
@@ -7,6 +7,7 @@ use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
 use std::path::Path;
 use std::process::exit;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use std::{env, fs};
 use tokio::net::{TcpListener, TcpStream};
@@ -15,9 +16,14 @@ use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 use tokio::time::{interval, Duration};
 
+mod blocklist;
 mod logging;
+mod metrics;
+mod service;
 mod socks5;
 mod stats;
+mod transport;
+mod udp;
 mod utils;
 
 type SharedStats = Arc<Mutex<HashMap<IpAddr, stats::PacketStats>>>;
@@ -27,12 +33,52 @@ struct Config {
     network: NetworkConfig,
     #[serde(default)]
     logging: LoggingConfig,
+    #[serde(default)]
+    blocklist: BlocklistConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    service: ServiceConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ServiceConfig {
+    #[serde(default = "default_service_mode")]
+    mode: String,
+}
+
+fn default_service_mode() -> String {
+    "daemon".to_string()
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MetricsConfig {
+    #[serde(default)]
+    listen: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BlocklistConfig {
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    feed: Option<String>,
+    #[serde(default)]
+    check_source: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct NetworkConfig {
     listen: String,
     socks5: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    udp_listen: Option<String>,
+    #[serde(default = "default_transport")]
+    transport: String,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -45,6 +91,9 @@ struct LoggingConfig {
     rotate_count: usize,
 }
 
+fn default_transport() -> String {
+    "tcp".to_string()
+}
 fn default_logging_enabled() -> bool {
     true
 }
@@ -56,9 +105,21 @@ fn default_rotate_count() -> usize {
 }
 
 fn main() {
-    if let Err(e) = daemonize() {
-        eprintln!("daemonizing error: {}", e);
-        exit(1);
+    let config = match read_config("/etc/blksocks/config.toml") {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("config loading error: {}", e);
+            exit(1);
+        }
+    };
+
+    // Modern supervisors expect the process to stay in the foreground; only the
+    // classic daemon mode double-forks.
+    if config.service.mode != "systemd" {
+        if let Err(e) = daemonize() {
+            eprintln!("daemonizing error: {}", e);
+            exit(1);
+        }
     }
 
     let runtime = match Runtime::new() {
@@ -69,23 +130,34 @@ fn main() {
         }
     };
     runtime.block_on(async {
-        blk_main().await;
+        blk_main(config).await;
     });
 }
 
-async fn blk_main() {
-    let config = match read_config("/etc/blksocks/config.toml") {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("config loading error: {}", e);
-            return;
-        }
-    };
+async fn blk_main(config: Config) {
+    let systemd = config.service.mode == "systemd";
 
     let packet_stats = Arc::new(Mutex::new(HashMap::<IpAddr, stats::PacketStats>::new()));
     tokio::spawn(expire_old_entries(Arc::clone(&packet_stats)));
     tokio::spawn(handle_user1(Arc::clone(&packet_stats)));
 
+    let blocklist = blocklist::load(&config.blocklist).await;
+    if let Some(feed) = config.blocklist.feed.clone() {
+        tokio::spawn(blocklist::subscribe(feed, Arc::clone(&blocklist)));
+    }
+    let check_source = config.blocklist.check_source;
+
+    let active: metrics::ActiveConnections = Arc::new(AtomicI64::new(0));
+    if let Some(listen) = config.metrics.listen.clone() {
+        let packet_stats = Arc::clone(&packet_stats);
+        let active = Arc::clone(&active);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&listen, packet_stats, active).await {
+                log::error!("metrics endpoint error: {}", e);
+            }
+        });
+    }
+
     let addr = config.network.listen;
     let listener = match TcpListener::bind(&addr).await {
         Ok(x) => x,
@@ -97,16 +169,47 @@ async fn blk_main() {
 
     logging::setup(&config.logging);
 
-    // do not close fds until end of all possible error reports
-    null_fd(0);
-    null_fd(1);
-    null_fd(2);
+    // Under systemd journald captures stdout/stderr; only the daemon mode
+    // detaches from the controlling terminal.
+    if !systemd {
+        // do not close fds until end of all possible error reports
+        null_fd(0);
+        null_fd(1);
+        null_fd(2);
+    }
 
     log::info!("Server started on {}", &addr);
     log::info!("using proxy: {}", &config.network.socks5);
 
+    if systemd {
+        service::notify_ready();
+        service::spawn_watchdog();
+        tokio::spawn(handle_reload());
+    }
+
+    if let Some(udp_listen) = config.network.udp_listen.clone() {
+        let addr_socks5 = config.network.socks5.clone();
+        let transport = config.network.transport.clone();
+        let username = config.network.username.clone();
+        let password = config.network.password.clone();
+        let packet_stats = Arc::clone(&packet_stats);
+        tokio::spawn(async move {
+            if let Err(e) =
+                udp::run(&udp_listen, addr_socks5, transport, username, password, packet_stats)
+                    .await
+            {
+                log::error!("udp relay error: {}", e);
+            }
+        });
+    }
+
     loop {
         let addr_socks5 = config.network.socks5.clone();
+        let username = config.network.username.clone();
+        let password = config.network.password.clone();
+        let transport = config.network.transport.clone();
+        let blocklist = Arc::clone(&blocklist);
+        let active = Arc::clone(&active);
         let packet_stats = Arc::clone(&packet_stats);
 
         let (socket, _) = match listener.accept().await {
@@ -118,7 +221,19 @@ async fn blk_main() {
         };
 
         tokio::spawn(async move {
-            let result = handle_client(socket, &addr_socks5, packet_stats).await;
+            active.fetch_add(1, Ordering::Relaxed);
+            let result = handle_client(
+                socket,
+                &addr_socks5,
+                &transport,
+                username,
+                password,
+                blocklist,
+                check_source,
+                Arc::clone(&packet_stats),
+            )
+            .await;
+            active.fetch_sub(1, Ordering::Relaxed);
             if let Err(e) = result {
                 log::info!("{}", e);
             }
@@ -129,15 +244,47 @@ async fn blk_main() {
 async fn handle_client(
     client_socket: TcpStream,
     addr_socks5: &str,
+    transport: &str,
+    username: Option<String>,
+    password: Option<String>,
+    blocklist: blocklist::SharedBlocklist,
+    check_source: bool,
     packet_stats: Arc<Mutex<HashMap<IpAddr, stats::PacketStats>>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let dest_addr = utils::get_dest_addr(&client_socket)?;
     log::info!("connecting to {}", &dest_addr);
 
-    let downstream_socket = socks5::proxy_conn(addr_socks5, &dest_addr).await?;
+    {
+        let set = blocklist.read().await;
+        if !set.is_empty() {
+            if let Ok(addr) = dest_addr.parse::<SocketAddr>() {
+                if set.contains(addr.ip()) {
+                    log::info!("rejected blocked destination {}", addr.ip());
+                    return Ok(());
+                }
+            }
+            if check_source {
+                if let Ok(peer) = client_socket.peer_addr() {
+                    if set.contains(peer.ip()) {
+                        log::info!("rejected blocked source {}", peer.ip());
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    if let Ok(addr) = dest_addr.parse::<SocketAddr>() {
+        let mut pstats = packet_stats.lock().await;
+        stats::note_connection(&mut pstats, addr.ip());
+    }
+
+    let upstream = transport::connect(addr_socks5, transport).await?;
+    let downstream_socket =
+        socks5::proxy_conn(upstream, &dest_addr, username.as_deref(), password.as_deref()).await?;
 
     let (mut client_reader, mut client_writer) = client_socket.into_split();
-    let (mut downstream_reader, mut downstream_writer) = downstream_socket.into_split();
+    let (mut downstream_reader, mut downstream_writer) = tokio::io::split(downstream_socket);
 
     let dest_addr_clone = dest_addr.clone();
     let packet_stats_clone = Arc::clone(&packet_stats);
@@ -208,6 +355,24 @@ async fn handle_user1(shared_stats: SharedStats) {
     }
 }
 
+async fn handle_reload() {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("handle sighup error: {}", e);
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        // Report RELOADING=1 then READY=1 to the service manager. The config
+        // file is not re-read and the running listeners are left unchanged.
+        service::notify_reloading();
+        log::info!("config reload requested");
+        service::notify_ready();
+    }
+}
+
 fn daemonize() -> Result<(), std::io::Error> {
     let pid = unsafe { libc::fork() };
     if pid < 0 {
@@ -0,0 +1,104 @@
+use crate::stats;
+use crate::SharedStats;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+/// Number of in-flight client connections, surfaced as a Prometheus gauge.
+pub type ActiveConnections = Arc<AtomicI64>;
+
+/// Serve `/stats.json` and `/metrics` on the configured address until the
+/// process exits. Enabled only when `[metrics] listen` is set in config.
+pub async fn serve(
+    listen: &str,
+    packet_stats: SharedStats,
+    active: ActiveConnections,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr: SocketAddr = listen.parse()?;
+
+    let make_service = make_service_fn(move |_conn| {
+        let packet_stats = SharedStats::clone(&packet_stats);
+        let active = Arc::clone(&active);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                route(req, SharedStats::clone(&packet_stats), Arc::clone(&active))
+            }))
+        }
+    });
+
+    log::info!("metrics endpoint started on {}", listen);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+async fn route(
+    req: Request<Body>,
+    packet_stats: SharedStats,
+    active: ActiveConnections,
+) -> Result<Response<Body>, Infallible> {
+    let response = match req.uri().path() {
+        "/stats.json" => stats_json(packet_stats).await,
+        "/metrics" => prometheus(packet_stats, active).await,
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found\n"))
+            .unwrap(),
+    };
+    Ok(response)
+}
+
+async fn stats_json(packet_stats: SharedStats) -> Response<Body> {
+    let snapshot = {
+        let pstats = packet_stats.lock().await;
+        stats::snapshot(&pstats)
+    };
+    match serde_json::to_vec(&snapshot) {
+        Ok(body) => Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("{}\n", e)))
+            .unwrap(),
+    }
+}
+
+async fn prometheus(packet_stats: SharedStats, active: ActiveConnections) -> Response<Body> {
+    let snapshot = {
+        let pstats = packet_stats.lock().await;
+        stats::snapshot(&pstats)
+    };
+
+    let mut out = String::new();
+    out.push_str("# HELP blksocks_bytes_total Bytes relayed per destination IP.\n");
+    out.push_str("# TYPE blksocks_bytes_total counter\n");
+    for stat in &snapshot {
+        out.push_str(&format!(
+            "blksocks_bytes_total{{ip=\"{}\"}} {}\n",
+            stat.ip, stat.byte_count
+        ));
+    }
+    out.push_str("# HELP blksocks_connections_total Connections per destination IP.\n");
+    out.push_str("# TYPE blksocks_connections_total counter\n");
+    for stat in &snapshot {
+        out.push_str(&format!(
+            "blksocks_connections_total{{ip=\"{}\"}} {}\n",
+            stat.ip, stat.connections
+        ));
+    }
+    out.push_str("# HELP blksocks_active_connections Currently open client connections.\n");
+    out.push_str("# TYPE blksocks_active_connections gauge\n");
+    out.push_str(&format!(
+        "blksocks_active_connections {}\n",
+        active.load(Ordering::Relaxed)
+    ));
+
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(out))
+        .unwrap()
+}
@@ -0,0 +1,38 @@
+use sd_notify::NotifyState;
+use tokio::time::{interval, Duration};
+
+/// Tell the service manager the process is ready to serve. No-op (beyond a log
+/// line on error) when not running under a `Type=notify` unit.
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        log::error!("sd_notify READY failed: {}", e);
+    }
+}
+
+/// Send `RELOADING=1` at the start of a configuration reload. `READY=1` is not
+/// sent here; call `notify_ready` once the reload is done.
+pub fn notify_reloading() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Reloading]) {
+        log::error!("sd_notify RELOADING failed: {}", e);
+    }
+}
+
+/// Spawn a task that pings the systemd watchdog at half the `WATCHDOG_USEC`
+/// interval. Does nothing if the unit has no watchdog configured.
+pub fn spawn_watchdog() {
+    let mut usec = 0u64;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+
+    let period = Duration::from_micros(usec / 2);
+    tokio::spawn(async move {
+        let mut tick = interval(period);
+        loop {
+            tick.tick().await;
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                log::error!("sd_notify WATCHDOG failed: {}", e);
+            }
+        }
+    });
+}
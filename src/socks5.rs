@@ -1,58 +1,252 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::transport::{self, BoxedTransport};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const SOCKS_VERSION: u8 = 5;
 const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
 const ADDR_TYPE_IPV4: u8 = 1;
 const ADDR_TYPE_DOMAIN: u8 = 3;
+const ADDR_TYPE_IPV6: u8 = 4;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERPASS: u8 = 0x02;
 
-pub async fn proxy_conn(
+/// Run the SOCKS5 CONNECT negotiation over an already-established upstream
+/// transport. Generic over the stream so the same handshake runs over plain
+/// TCP, TLS, or a WebSocket (see `transport::connect`).
+pub async fn proxy_conn<S>(
+    mut stream: S,
+    dest_addr: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<S, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    method_negotiation(&mut stream, username, password).await?;
+
+    // Send SOCKS version, command, and dest address
+    let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    req.extend_from_slice(&encode_socks_addr(dest_addr)?);
+    stream.write_all(&req).await?;
+    read_socks_reply(&mut stream).await?;
+
+    Ok(stream)
+}
+
+/// Issue a UDP ASSOCIATE (`0x03`) over the configured upstream transport and
+/// return the control stream together with the relay endpoint the server
+/// echoed in BND.ADDR/BND.PORT. The control stream must be kept alive for as
+/// long as the UDP association is in use. Goes through `transport::connect`
+/// like `handle_client` does for CONNECT, so the control channel (and the
+/// fact that this is a SOCKS5 client at all) gets the same TLS/WebSocket
+/// cover as the TCP path.
+pub async fn udp_associate(
     proxy_addr: &str,
+    upstream_transport: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<(BoxedTransport, SocketAddr), Box<dyn std::error::Error + Send + Sync>> {
+    let mut stream = transport::connect(proxy_addr, upstream_transport).await?;
+
+    method_negotiation(&mut stream, username, password).await?;
+
+    // The client does not yet know which local port it will send from, so we
+    // advertise the wildcard endpoint and let the relay accept any source.
+    let req = [SOCKS_VERSION, CMD_UDP_ASSOCIATE, 0, ADDR_TYPE_IPV4, 0, 0, 0, 0, 0, 0];
+    stream.write_all(&req).await?;
+    let mut relay = read_socks_reply(&mut stream).await?;
+
+    // A BND.ADDR of 0.0.0.0/[::] means "reuse the control connection's host".
+    // The control stream may be wrapped in TLS/WebSocket, so resolve the
+    // upstream's address ourselves rather than relying on a `peer_addr()`
+    // that only a bare `TcpStream` has.
+    if relay.ip().is_unspecified() {
+        let resolved = tokio::net::lookup_host(proxy_addr)
+            .await?
+            .next()
+            .ok_or("could not resolve socks5 proxy address")?;
+        relay = SocketAddr::new(resolved.ip(), relay.port());
+    }
+
+    Ok((stream, relay))
+}
+
+/// Encode the SOCKS5 `ATYP DST.ADDR DST.PORT` triplet shared by the CONNECT
+/// request and the per-datagram UDP request header. The port is split from the
+/// right so bracketed IPv6 literals (`[::1]:443`) survive.
+pub fn encode_socks_addr(
     dest_addr: &str,
-) -> Result<TcpStream, Box<dyn std::error::Error + Send + Sync>> {
-    let mut stream = match TcpStream::connect(proxy_addr).await {
-        Ok(s) => s,
-        Err(e) => return Err(format!("to socks5 server: {}", e).into()),
-    };
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let (host, port) = dest_addr
+        .rsplit_once(':')
+        .ok_or("destination address missing port")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let dest_port = port.parse::<u16>()?;
+
+    let mut out = Vec::new();
+    if let Ok(v4) = host.parse::<std::net::Ipv4Addr>() {
+        out.push(ADDR_TYPE_IPV4);
+        out.extend_from_slice(&v4.octets());
+    } else if let Ok(v6) = host.parse::<std::net::Ipv6Addr>() {
+        out.push(ADDR_TYPE_IPV6);
+        out.extend_from_slice(&v6.octets());
+    } else {
+        out.push(ADDR_TYPE_DOMAIN);
+        out.push(host.len() as u8);
+        out.extend_from_slice(host.as_bytes());
+    }
+    out.extend_from_slice(&dest_port.to_be_bytes());
 
-    // Send SOCKS version and authentication methods
-    stream.write_all(&[SOCKS_VERSION, 1, 0]).await?;
+    Ok(out)
+}
+
+async fn method_negotiation<S>(
+    stream: &mut S,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Offer both no-auth and username/password methods (RFC 1929)
+    stream.write_all(&[SOCKS_VERSION, 2, AUTH_NONE, AUTH_USERPASS]).await?;
     let mut buf = [0; 2];
     stream.read_exact(&mut buf).await?;
 
-    // Check for SOCKS version and authentication method
-    if buf[0] != SOCKS_VERSION || buf[1] != 0 {
+    // Check for SOCKS version and the method the server selected
+    if buf[0] != SOCKS_VERSION {
         return Err("Invalid SOCKS version or authentication method".into());
     }
+    match buf[1] {
+        AUTH_NONE => Ok(()),
+        AUTH_USERPASS => userpass_auth(stream, username, password).await,
+        _ => Err("Invalid SOCKS version or authentication method".into()),
+    }
+}
 
-    // Send SOCKS version, command, and dest address type
-    let dest_addr_parts: Vec<&str> = dest_addr.split(':').collect();
-    let dest_addr_str = dest_addr_parts[0];
-    let dest_port = dest_addr_parts[1].parse::<u16>()?;
-    let dest_port_bytes = dest_port.to_be_bytes();
-    let addr_type = if dest_addr_str.parse::<std::net::Ipv4Addr>().is_ok() {
-        ADDR_TYPE_IPV4
-    } else {
-        ADDR_TYPE_DOMAIN
-    };
+// Consume a variable-length SOCKS5 reply and return its BND endpoint. The
+// BND.ADDR length depends on ATYP, so a fixed 10-byte read desyncs the stream
+// on IPv6/domain binds.
+async fn read_socks_reply<S>(
+    stream: &mut S,
+) -> Result<SocketAddr, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0; 4]; // VER REP RSV ATYP
+    stream.read_exact(&mut head).await?;
 
-    let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0, addr_type];
-    match addr_type {
+    if head[1] != 0x00 {
+        return Err(format!("socks5 server rejected request: REP=0x{:02x}", head[1]).into());
+    }
+
+    let ip: std::net::IpAddr = match head[3] {
         ADDR_TYPE_IPV4 => {
-            req.extend_from_slice(&dest_addr_str.parse::<std::net::Ipv4Addr>()?.octets());
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            std::net::Ipv4Addr::from(octets).into()
+        }
+        ADDR_TYPE_IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            std::net::Ipv6Addr::from(octets).into()
         }
         ADDR_TYPE_DOMAIN => {
-            let addr_len = dest_addr_str.len() as u8;
-            req.push(addr_len);
-            req.extend_from_slice(dest_addr_str.as_bytes());
+            let mut len = [0; 1];
+            stream.read_exact(&mut len).await?;
+            let mut name = vec![0; len[0] as usize];
+            stream.read_exact(&mut name).await?;
+            // A domain BND.ADDR cannot be turned into a SocketAddr; fall back to
+            // the unspecified address after draining the port below.
+            std::net::Ipv4Addr::UNSPECIFIED.into()
         }
-        _ => return Err("Unsupported address type".into()),
-    }
+        _ => return Err("Unsupported address type in reply".into()),
+    };
+
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
 
-    req.extend_from_slice(&dest_port_bytes);
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+// RFC 1929 username/password sub-negotiation.
+async fn userpass_auth<S>(
+    stream: &mut S,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let username = username.unwrap_or("").as_bytes();
+    let password = password.unwrap_or("").as_bytes();
+
+    let mut req = vec![0x01, username.len() as u8];
+    req.extend_from_slice(username);
+    req.push(password.len() as u8);
+    req.extend_from_slice(password);
     stream.write_all(&req).await?;
-    let mut buf = [0; 10];
+
+    let mut buf = [0; 2];
     stream.read_exact(&mut buf).await?;
+    if buf[1] != 0x00 {
+        return Err("SOCKS5 username/password authentication failed".into());
+    }
 
-    Ok(stream)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode the `ATYP DST.ADDR DST.PORT` triplet back into (host, port),
+    // mirroring how a real SOCKS5 peer would read it off the wire.
+    fn decode_socks_addr(bytes: &[u8]) -> (String, u16) {
+        let (host, rest) = match bytes[0] {
+            ADDR_TYPE_IPV4 => {
+                let octets: [u8; 4] = bytes[1..5].try_into().unwrap();
+                (std::net::Ipv4Addr::from(octets).to_string(), &bytes[5..])
+            }
+            ADDR_TYPE_IPV6 => {
+                let octets: [u8; 16] = bytes[1..17].try_into().unwrap();
+                (std::net::Ipv6Addr::from(octets).to_string(), &bytes[17..])
+            }
+            ADDR_TYPE_DOMAIN => {
+                let len = bytes[1] as usize;
+                let name = std::str::from_utf8(&bytes[2..2 + len]).unwrap().to_string();
+                (name, &bytes[2 + len..])
+            }
+            other => panic!("unexpected ATYP {}", other),
+        };
+        let port = u16::from_be_bytes([rest[0], rest[1]]);
+        (host, port)
+    }
+
+    #[test]
+    fn encode_socks_addr_round_trips_ipv4() {
+        let encoded = encode_socks_addr("93.184.216.34:443").unwrap();
+        assert_eq!(encoded[0], ADDR_TYPE_IPV4);
+        assert_eq!(decode_socks_addr(&encoded), ("93.184.216.34".to_string(), 443));
+    }
+
+    #[test]
+    fn encode_socks_addr_round_trips_ipv6_literal() {
+        let encoded = encode_socks_addr("[2001:db8::1]:53").unwrap();
+        assert_eq!(encoded[0], ADDR_TYPE_IPV6);
+        assert_eq!(decode_socks_addr(&encoded), ("2001:db8::1".to_string(), 53));
+    }
+
+    #[test]
+    fn encode_socks_addr_round_trips_domain() {
+        let encoded = encode_socks_addr("example.com:80").unwrap();
+        assert_eq!(encoded[0], ADDR_TYPE_DOMAIN);
+        assert_eq!(decode_socks_addr(&encoded), ("example.com".to_string(), 80));
+    }
+
+    #[test]
+    fn encode_socks_addr_rejects_missing_port() {
+        assert!(encode_socks_addr("example.com").is_err());
+    }
 }
@@ -1,15 +1,27 @@
+use serde_derive::Serialize;
 use std::collections::HashMap;
 use time::{Duration, OffsetDateTime};
 use std::net::IpAddr;
 
 pub struct PacketStats {
     byte_count: u64,
+    connections: u64,
     last_updated: OffsetDateTime,
 }
 
+/// A serializable view of one peer's counters, used by the metrics endpoint.
+#[derive(Serialize)]
+pub struct StatSnapshot {
+    pub ip: String,
+    pub byte_count: u64,
+    pub connections: u64,
+    pub last_updated: i64,
+}
+
 pub fn update_stats(stats: &mut HashMap<IpAddr, PacketStats>, ip: IpAddr, bytes: u64) {
     let entry = stats.entry(ip).or_insert(PacketStats {
         byte_count: 0,
+        connections: 0,
         last_updated: OffsetDateTime::now_utc(),
     });
 
@@ -17,6 +29,29 @@ pub fn update_stats(stats: &mut HashMap<IpAddr, PacketStats>, ip: IpAddr, bytes:
     entry.last_updated = OffsetDateTime::now_utc();
 }
 
+pub fn note_connection(stats: &mut HashMap<IpAddr, PacketStats>, ip: IpAddr) {
+    let entry = stats.entry(ip).or_insert(PacketStats {
+        byte_count: 0,
+        connections: 0,
+        last_updated: OffsetDateTime::now_utc(),
+    });
+
+    entry.connections += 1;
+    entry.last_updated = OffsetDateTime::now_utc();
+}
+
+pub fn snapshot(stats: &HashMap<IpAddr, PacketStats>) -> Vec<StatSnapshot> {
+    stats
+        .iter()
+        .map(|(ip, stat)| StatSnapshot {
+            ip: ip.to_string(),
+            byte_count: stat.byte_count,
+            connections: stat.connections,
+            last_updated: stat.last_updated.unix_timestamp(),
+        })
+        .collect()
+}
+
 pub fn expire_old_entries(stats: &mut HashMap<IpAddr, PacketStats>) {
     let expiry_threshold = OffsetDateTime::now_utc() - Duration::days(7);
     stats.retain(|_, entry| entry.last_updated > expiry_threshold);
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+/// Any upstream byte stream the SOCKS5 handshake can run over.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type BoxedTransport = Box<dyn Transport>;
+
+/// Establish the upstream connection to the SOCKS5 server using the configured
+/// transport: plaintext TCP, TLS (native roots), or a WebSocket tunnel. The
+/// returned stream is ready for `socks5::proxy_conn` to negotiate over.
+pub async fn connect(
+    proxy_addr: &str,
+    transport: &str,
+) -> Result<BoxedTransport, Box<dyn std::error::Error + Send + Sync>> {
+    match transport {
+        "tcp" => {
+            let stream = TcpStream::connect(proxy_addr).await?;
+            Ok(Box::new(stream))
+        }
+        "tls" => Ok(Box::new(connect_tls(proxy_addr).await?)),
+        "ws" | "wss" => Ok(Box::new(connect_ws(proxy_addr, transport).await?)),
+        other => Err(format!("unknown upstream transport: {}", other).into()),
+    }
+}
+
+async fn connect_tls(
+    proxy_addr: &str,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>, Box<dyn std::error::Error + Send + Sync>> {
+    let host = proxy_addr
+        .rsplit_once(':')
+        .map(|(h, _)| h)
+        .unwrap_or(proxy_addr);
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+
+    let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs()? {
+        roots.add(cert)?;
+    }
+    let config = tokio_rustls::rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(host)?;
+
+    let tcp = TcpStream::connect(proxy_addr).await?;
+    Ok(connector.connect(server_name, tcp).await?)
+}
+
+async fn connect_ws(
+    proxy_addr: &str,
+    scheme: &str,
+) -> Result<impl Transport, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("{}://{}/", scheme, proxy_addr);
+    let (ws, _resp) = async_tungstenite::tokio::connect_async(url).await?;
+    // Carry the SOCKS5 bytes inside binary frames via a byte-stream adapter.
+    Ok(ws_stream_tungstenite::WsStream::new(ws).compat())
+}
@@ -0,0 +1,431 @@
+use crate::socks5;
+use crate::stats;
+use crate::SharedStats;
+use nix::libc;
+use nix::sys::socket::{
+    bind, recvmsg, setsockopt, socket, sockopt, AddressFamily, ControlMessageOwned, MsgFlags,
+    SockFlag, SockType, SockaddrStorage,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{IoSlice, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+// SOCKS5 UDP requests are framed with a fixed 3-byte prefix (RSV RSV FRAG)
+// ahead of the ATYP/ADDR/PORT triplet; we never fragment, so FRAG is 0.
+const UDP_HEADER_PREFIX: [u8; 3] = [0, 0, 0];
+const ASSOCIATION_IDLE: Duration = Duration::from_secs(60);
+const UDP_ASSOCIATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Upstream connection parameters shared read-only by every forwarding task.
+struct Upstream {
+    addr_socks5: String,
+    transport: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+struct Association {
+    relay: Arc<UdpSocket>,
+    // The control connection must outlive the association or the server
+    // tears the UDP relay down.
+    _control: crate::transport::BoxedTransport,
+    // Keeps draining upstream replies for this association. UDP gives us no
+    // EOF to stop it naturally, so it must be aborted explicitly once the
+    // association is evicted or replaced.
+    worker: JoinHandle<()>,
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.worker.abort();
+    }
+}
+
+// One entry per client source address. `assoc` is filled in at most once, via
+// `OnceCell::get_or_try_init`, so concurrent datagrams that miss the cache for
+// the same source single-flight onto one `udp_associate`/`connect_relay`
+// instead of racing each other.
+struct Slot {
+    assoc: OnceCell<Association>,
+    last_seen: std::sync::Mutex<Instant>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Slot {
+            assoc: OnceCell::new(),
+            last_seen: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.last_seen.lock().unwrap())
+    }
+}
+
+type Associations = Arc<Mutex<HashMap<SocketAddr, Arc<Slot>>>>;
+
+/// Bind a transparent UDP ingress socket and relay redirected datagrams through
+/// the upstream SOCKS5 server via UDP ASSOCIATE. Mirrors the TCP path in
+/// `handle_client`: one association per client source address, with idle expiry.
+pub async fn run(
+    listen: &str,
+    addr_socks5: String,
+    transport: String,
+    username: Option<String>,
+    password: Option<String>,
+    packet_stats: SharedStats,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listen_addr: SocketAddr = listen.parse()?;
+    let ingress = Arc::new(bind_transparent(listen_addr)?);
+    let associations: Associations = Arc::new(Mutex::new(HashMap::new()));
+    let upstream = Arc::new(Upstream {
+        addr_socks5,
+        transport,
+        username,
+        password,
+    });
+
+    tokio::spawn(expire_associations(Arc::clone(&associations)));
+
+    log::info!("UDP transparent relay started on {}", listen);
+
+    let mut buf = vec![0u8; 65_535];
+    loop {
+        let (payload, src, orig_dst) = match recv_with_origdst(&ingress, &mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("udp recvmsg failed: {}", e);
+                continue;
+            }
+        };
+
+        // Hand each datagram to its own task: a cache miss runs a full SOCKS5
+        // handshake to the upstream, and nothing here should block delivery
+        // for already-established associations of other clients.
+        let associations = Arc::clone(&associations);
+        let upstream = Arc::clone(&upstream);
+        let packet_stats = SharedStats::clone(&packet_stats);
+        tokio::spawn(async move {
+            if let Err(e) =
+                forward_datagram(&associations, &upstream, src, orig_dst, &payload, &packet_stats)
+                    .await
+            {
+                log::info!("udp forward to {} failed: {}", orig_dst, e);
+            }
+        });
+    }
+}
+
+async fn forward_datagram(
+    associations: &Associations,
+    upstream: &Upstream,
+    src: SocketAddr,
+    orig_dst: SocketAddr,
+    payload: &[u8],
+    packet_stats: &SharedStats,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Only the map lookup/insert happens under the lock; the SOCKS5 handshake
+    // for a cache miss runs afterwards, so one slow/hung upstream can't stall
+    // datagrams for any other association.
+    let slot = {
+        let mut map = associations.lock().await;
+        Arc::clone(map.entry(src).or_insert_with(|| Arc::new(Slot::new())))
+    };
+    slot.touch();
+
+    let relay = Arc::clone(&establish(&slot, upstream, src, packet_stats).await?.relay);
+
+    // Prepend the SOCKS5 UDP request header and ship the datagram.
+    let mut frame = UDP_HEADER_PREFIX.to_vec();
+    frame.extend_from_slice(&socks5::encode_socks_addr(&orig_dst.to_string())?);
+    frame.extend_from_slice(payload);
+    relay.send(&frame).await?;
+
+    let mut pstats = packet_stats.lock().await;
+    stats::update_stats(&mut pstats, orig_dst.ip(), payload.len() as u64);
+
+    Ok(())
+}
+
+// Establish (or return the already-established) association for `slot`. When
+// several datagrams for the same new source race each other, `OnceCell`
+// ensures only the first actually dials the upstream and the rest just await
+// its result.
+async fn establish<'a>(
+    slot: &'a Slot,
+    upstream: &Upstream,
+    src: SocketAddr,
+    packet_stats: &SharedStats,
+) -> Result<&'a Association, Box<dyn std::error::Error + Send + Sync>> {
+    slot.assoc
+        .get_or_try_init(|| async {
+            let (control, relay_addr) = tokio::time::timeout(
+                UDP_ASSOCIATE_TIMEOUT,
+                socks5::udp_associate(
+                    &upstream.addr_socks5,
+                    &upstream.transport,
+                    upstream.username.as_deref(),
+                    upstream.password.as_deref(),
+                ),
+            )
+            .await
+            .map_err(|_| "udp associate handshake timed out")??;
+
+            let relay = Arc::new(connect_relay(relay_addr).await?);
+            let worker = tokio::spawn(relay_to_client(
+                Arc::clone(&relay),
+                src,
+                SharedStats::clone(packet_stats),
+            ));
+
+            Ok(Association {
+                relay,
+                _control: control,
+                worker,
+            })
+        })
+        .await
+}
+
+// Read relay responses for one association and spoof each one back to the
+// client from the address that actually sent it. A client can legitimately
+// talk to more than one remote host over a single association (a second DNS
+// resolver, a QUIC connection migrating to a new server IP), and each
+// reply's own DST.ADDR/DST.PORT tells us who that was — so the reply socket
+// is bound per source address instead of once for the whole association.
+async fn relay_to_client(relay: Arc<UdpSocket>, src: SocketAddr, packet_stats: SharedStats) {
+    let mut reply_sockets: HashMap<SocketAddr, UdpSocket> = HashMap::new();
+
+    let mut buf = vec![0u8; 65_535];
+    loop {
+        let n = match relay.recv(&mut buf).await {
+            Ok(n) => n,
+            Err(_) => break,
+        };
+        let (remote, payload) = match parse_udp_reply(&buf[..n]) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let reply = match reply_sockets.entry(remote) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match bind_transparent(remote) {
+                Ok(s) => e.insert(s),
+                Err(err) => {
+                    log::error!("udp reply socket for {} failed: {}", remote, err);
+                    continue;
+                }
+            },
+        };
+
+        if let Err(e) = reply.send_to(payload, src).await {
+            log::info!("udp reply to {} failed: {}", src, e);
+        }
+
+        let mut pstats = packet_stats.lock().await;
+        stats::update_stats(&mut pstats, remote.ip(), payload.len() as u64);
+    }
+}
+
+// Decode `RSV(2) FRAG(1) ATYP DST.ADDR DST.PORT` from a relay reply, giving
+// back the address that actually sent it together with its payload. Unlike
+// the request side, a domain-name DST.ADDR here can't be turned into a real
+// peer address to spoof the reply from, so it's treated as unsupported.
+fn parse_udp_reply(frame: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if frame.len() < 4 || frame[2] != 0 {
+        return None;
+    }
+    let (ip, addr_len): (IpAddr, usize) = match frame[3] {
+        1 => (Ipv4Addr::from(<[u8; 4]>::try_from(frame.get(4..8)?).ok()?).into(), 4),
+        4 => (Ipv6Addr::from(<[u8; 16]>::try_from(frame.get(4..20)?).ok()?).into(), 16),
+        _ => return None,
+    };
+    let port_off = 4 + addr_len;
+    let port = u16::from_be_bytes(frame.get(port_off..port_off + 2)?.try_into().ok()?);
+    let payload = frame.get(port_off + 2..)?;
+    Some((SocketAddr::new(ip, port), payload))
+}
+
+async fn connect_relay(
+    relay_addr: SocketAddr,
+) -> Result<UdpSocket, Box<dyn std::error::Error + Send + Sync>> {
+    let bind_addr = if relay_addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(relay_addr).await?;
+    Ok(socket)
+}
+
+async fn expire_associations(associations: Associations) {
+    let mut interval = tokio::time::interval(ASSOCIATION_IDLE);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut map = associations.lock().await;
+        // Dropping the evicted `Slot` drops its `Association`, which aborts
+        // the `relay_to_client` task and closes its sockets; UDP has no EOF to
+        // do that for us.
+        map.retain(|_, slot| slot.idle_for(now) < ASSOCIATION_IDLE);
+    }
+}
+
+// Bind a UDP socket with IP(V6)_TRANSPARENT and IP(V6)_RECVORIGDSTADDR so that
+// TPROXY-redirected datagrams both land here and carry their original
+// destination in the recvmsg control messages.
+fn bind_transparent(addr: SocketAddr) -> Result<UdpSocket, Box<dyn std::error::Error + Send + Sync>> {
+    let family = if addr.is_ipv6() {
+        AddressFamily::Inet6
+    } else {
+        AddressFamily::Inet
+    };
+    let fd = socket(family, SockType::Datagram, SockFlag::empty(), None)?;
+
+    setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    if addr.is_ipv6() {
+        setsockopt(&fd, sockopt::Ipv6TransparentMode, &true)?;
+        setsockopt(&fd, sockopt::Ipv6RecvOrigDstAddr, &true)?;
+    } else {
+        setsockopt(&fd, sockopt::IpTransparent, &true)?;
+        setsockopt(&fd, sockopt::Ipv4RecvOrigDstAddr, &true)?;
+    }
+
+    bind(fd.as_raw_fd(), &SockaddrStorage::from(addr))?;
+
+    let std_sock: std::net::UdpSocket = OwnedFd::from(fd).into();
+    std_sock.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(std_sock)?)
+}
+
+// recvmsg a single datagram and recover its original destination from the
+// IP_ORIGDSTADDR / IPV6_ORIGDSTADDR control message.
+async fn recv_with_origdst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> Result<(Vec<u8>, SocketAddr, SocketAddr), Box<dyn std::error::Error + Send + Sync>> {
+    loop {
+        socket.readable().await?;
+        let fd = socket.as_raw_fd();
+        let mut cmsg_space = nix::cmsg_space!(libc::sockaddr_in6);
+        let res = socket.try_io(tokio::io::Interest::READABLE, || {
+            let mut iov = [IoSliceMut::new(buf)];
+            let msg = recvmsg::<SockaddrStorage>(
+                fd,
+                &mut iov,
+                Some(&mut cmsg_space),
+                MsgFlags::empty(),
+            )
+            .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+            let len = msg.bytes;
+            let src = sockaddr_to_socketaddr(msg.address);
+            let mut orig_dst = None;
+            for cmsg in msg.cmsgs().map_err(std::io::Error::other)? {
+                if let ControlMessageOwned::Ipv4OrigDstAddr(a) = cmsg {
+                    orig_dst = Some(sockaddr_in_to_socketaddr(a));
+                } else if let ControlMessageOwned::Ipv6OrigDstAddr(a) = cmsg {
+                    orig_dst = Some(sockaddr_in6_to_socketaddr(a));
+                }
+            }
+            Ok((len, src, orig_dst))
+        });
+
+        match res {
+            Ok((len, Some(src), Some(orig_dst))) => {
+                return Ok((buf[..len].to_vec(), src, orig_dst));
+            }
+            Ok(_) => return Err("datagram missing source or original destination".into()),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn sockaddr_to_socketaddr(addr: Option<SockaddrStorage>) -> Option<SocketAddr> {
+    let addr = addr?;
+    if let Some(v4) = addr.as_sockaddr_in() {
+        Some(SocketAddr::from(*v4))
+    } else {
+        addr.as_sockaddr_in6().map(|v6| SocketAddr::from(*v6))
+    }
+}
+
+fn sockaddr_in_to_socketaddr(a: libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::new(
+        std::net::Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)).into(),
+        u16::from_be(a.sin_port),
+    )
+}
+
+fn sockaddr_in6_to_socketaddr(a: libc::sockaddr_in6) -> SocketAddr {
+    SocketAddr::new(
+        std::net::Ipv6Addr::from(a.sin6_addr.s6_addr).into(),
+        u16::from_be(a.sin6_port),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(dest_addr: &str, payload: &[u8]) -> Vec<u8> {
+        let mut frame = UDP_HEADER_PREFIX.to_vec();
+        frame.extend_from_slice(&socks5::encode_socks_addr(dest_addr).unwrap());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn parse_udp_reply_round_trips_ipv4() {
+        let frame = framed("93.184.216.34:443", b"hello");
+        let (remote, payload) = parse_udp_reply(&frame).unwrap();
+        assert_eq!(remote, "93.184.216.34:443".parse().unwrap());
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn parse_udp_reply_round_trips_ipv6() {
+        let frame = framed("[2001:db8::1]:53", b"query");
+        let (remote, payload) = parse_udp_reply(&frame).unwrap();
+        assert_eq!(remote, "[2001:db8::1]:53".parse().unwrap());
+        assert_eq!(payload, b"query");
+    }
+
+    #[test]
+    fn parse_udp_reply_recovers_the_actual_replying_address() {
+        // A reply from a second remote (e.g. a second DNS resolver, or a QUIC
+        // server that migrated) must resolve to *that* address, not whatever
+        // the association's first destination was.
+        let first = framed("203.0.113.1:53", b"from-first");
+        let second = framed("203.0.113.2:53", b"from-second");
+        assert_eq!(parse_udp_reply(&first).unwrap().0.ip(), "203.0.113.1".parse::<IpAddr>().unwrap());
+        assert_eq!(parse_udp_reply(&second).unwrap().0.ip(), "203.0.113.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn parse_udp_reply_rejects_domain_address() {
+        let frame = framed("example.com:80", b"");
+        assert_eq!(parse_udp_reply(&frame), None);
+    }
+
+    #[test]
+    fn parse_udp_reply_rejects_fragmented_datagram() {
+        let mut frame = framed("93.184.216.34:443", b"hello");
+        frame[2] = 1; // non-zero FRAG
+        assert_eq!(parse_udp_reply(&frame), None);
+    }
+
+    #[test]
+    fn parse_udp_reply_rejects_truncated_frame() {
+        assert_eq!(parse_udp_reply(&[0, 0, 0]), None);
+    }
+}
@@ -1,9 +1,29 @@
+use nix::libc;
 use nix::sys::socket::{getsockopt, sockopt::OriginalDst};
 use std::io;
-use std::net::SocketAddrV4;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
 use tokio::net::TcpStream;
 
+// ip6tables netfilter sockopt for the redirected IPv6 destination.
+const SOL_IPV6: i32 = 41;
+const IP6T_SO_ORIGINAL_DST: i32 = 80;
+
 pub fn get_dest_addr(client_socket: &TcpStream) -> io::Result<String> {
+    // The redirected destination shares the listening socket's family, which we
+    // can read off the accepted peer address. A dual-stack (`::`) listener
+    // reports IPv4 clients as IPv4-mapped IPv6, so those still take the IPv4
+    // SO_ORIGINAL_DST path.
+    let native_v6 = match client_socket.peer_addr()?.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().is_none(),
+        IpAddr::V4(_) => false,
+    };
+    if native_v6 {
+        let addr_v6 = get_original_dst_v6(client_socket)?;
+        return Ok(format!("{}", addr_v6));
+    }
+
     let addr = getsockopt(&client_socket, OriginalDst)?;
     let addr_v4 = SocketAddrV4::new(
         u32::from_be(addr.sin_addr.s_addr).into(),
@@ -13,6 +33,30 @@ pub fn get_dest_addr(client_socket: &TcpStream) -> io::Result<String> {
     Ok(format!("{}", addr_v4))
 }
 
+fn get_original_dst_v6(client_socket: &TcpStream) -> io::Result<SocketAddrV6> {
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            client_socket.as_raw_fd(),
+            SOL_IPV6,
+            IP6T_SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(SocketAddrV6::new(
+        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+        u16::from_be(addr.sin6_port),
+        0,
+        0,
+    ))
+}
+
 pub fn _print_data(data: &[u8]) {
     match std::str::from_utf8(data) {
         Ok(display_str) => {